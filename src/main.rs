@@ -11,33 +11,17 @@ use uefi::println;
 const PCI_ADDR: u16 = 0xCF8;
 const PCI_DATA: u16 = 0xCFC;
 
-const BUS_UPSTREAM: u8 = 0x08;
-const BUS_DOWNSTREAM: u8 = 0x09;
-const BUS_GPU: u8 = 0x0A;
-
-const MEM_BASE: u16 = 0xE020;
-const MEM_LIMIT_ROOT: u16 = 0xE050; // 0xE050FFFF for root port (includes switch BAR)
-const MEM_LIMIT_CHILD: u16 = 0xE03F; // 0xE03FFFFF for downstream bridges
-
-const GPU_BAR0_LO: u32 = 0x3000_000C; // 0x1030000000, 64-bit pref
-const GPU_BAR0_HI: u32 = 0x0000_0010;
-const GPU_BAR2_LO: u32 = 0x4000_000C; // 0x1040000000, 64-bit pref  
-const GPU_BAR2_HI: u32 = 0x0000_0010;
-const GPU_BAR4: u32 = 0x0000_2001; // I/O @ 0x2000
-const GPU_BAR5: u32 = 0xE020_0000; // MMIO @ 0xE0200000
-const GPU_ROM: u32 = 0xE030_0001; // ROM @ 0xE0300000, enabled
-
-const AUDIO_BAR0: u32 = 0xE032_0000; // MMIO @ 0xE0320000
-
-const SWITCH_BAR0: u32 = 0xE040_0000; // MMIO @ 0xE0400000
-
-const PREF_BASE_LO: u16 = 0x3001;
-const PREF_LIMIT_LO: u16 = 0x4011;
-const PREF_BASE_HI: u32 = 0x10;
-const PREF_LIMIT_HI: u32 = 0x10;
-
-const IO_BASE: u8 = 0x20;
-const IO_LIMIT: u8 = 0x20;
+// Start of the address space each bump allocator hands out from. Chosen to
+// sit above whatever the platform firmware already claimed for bus 0.
+const IO_WINDOW_BASE: u32 = 0x2000;
+const MMIO_WINDOW_BASE: u32 = 0xE020_0000;
+const PREF_WINDOW_BASE: u64 = 0x10_3000_0000;
+
+// Granularity a bridge's base/limit registers decode at: 4KB for I/O,
+// 1MB for both non-prefetchable and prefetchable memory.
+const IO_WINDOW_GRANULARITY: u64 = 0x1000;
+const MEM_WINDOW_GRANULARITY: u64 = 0x10_0000;
+const PREF_WINDOW_GRANULARITY: u64 = 0x10_0000;
 
 #[inline(always)]
 fn pci_addr(bus: u8, dev: u8, func: u8, reg: u16) -> u32 {
@@ -99,12 +83,70 @@ fn w8(bus: u8, dev: u8, func: u8, reg: u16, val: u8) {
     out32(PCI_DATA, (o & mask) | ((val as u32) << shift));
 }
 
+// ECAM (MMCONFIG) base physical address for segment 0. Normally discovered
+// from the ACPI MCFG table; hardcoded here since MCFG parsing isn't wired up
+// yet. The legacy 0xCF8/0xCFC index/data ports only expose the first 256
+// bytes of config space, so extended capabilities (AER, ACS, ...) living at
+// offset >= 0x100 are only reachable through this path.
+const ECAM_BASE: u64 = 0xB000_0000;
+
+#[inline(always)]
+fn ecam_addr(bus: u8, dev: u8, func: u8, reg: u16) -> u64 {
+    ECAM_BASE + ((bus as u64) << 20) + ((dev as u64) << 15) + ((func as u64) << 12) + reg as u64
+}
+
+fn r32_ext(bus: u8, dev: u8, func: u8, reg: u16) -> u32 {
+    unsafe { core::ptr::read_volatile(ecam_addr(bus, dev, func, reg) as *const u32) }
+}
+
+fn r16_ext(bus: u8, dev: u8, func: u8, reg: u16) -> u16 {
+    unsafe { core::ptr::read_volatile(ecam_addr(bus, dev, func, reg) as *const u16) }
+}
+
+fn w32_ext(bus: u8, dev: u8, func: u8, reg: u16, val: u32) {
+    unsafe { core::ptr::write_volatile(ecam_addr(bus, dev, func, reg) as *mut u32, val) }
+}
+
+fn w16_ext(bus: u8, dev: u8, func: u8, reg: u16, val: u16) {
+    unsafe { core::ptr::write_volatile(ecam_addr(bus, dev, func, reg) as *mut u16, val) }
+}
+
+/// Walk the extended capability list (offset >= 0x100) looking for
+/// `target_id`. Each entry is a 32-bit header: cap ID in bits [15:0],
+/// next-offset in bits [31:20].
+fn find_ext_cap(bus: u8, dev: u8, func: u8, target_id: u16) -> Option<u16> {
+    let mut cap_ptr: u16 = 0x100;
+    while cap_ptr != 0 {
+        let header = r32_ext(bus, dev, func, cap_ptr);
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        let cap_id = (header & 0xFFFF) as u16;
+        if cap_id == target_id {
+            return Some(cap_ptr);
+        }
+        cap_ptr = ((header >> 20) & 0xFFC) as u16;
+    }
+    None
+}
+
 fn enable_cmd(bus: u8, dev: u8, func: u8) {
     let cmd = r16(bus, dev, func, 0x04);
     w16(bus, dev, func, 0x04, cmd | 0x0007); // IO | MEM | BUS MASTER
 }
 
-fn find_pcie_cap(bus: u8, dev: u8, func: u8) -> Option<u8> {
+/// Clear IO/MEM decode before the all-ones BAR sizing probe. A warm reboot
+/// or a prior partial bring-up can leave decode enabled on entry, and
+/// probing a BAR while its decode is live lets the sizing write alias real
+/// bus traffic.
+fn disable_decode(bus: u8, dev: u8, func: u8) {
+    let cmd = r16(bus, dev, func, 0x04);
+    w16(bus, dev, func, 0x04, cmd & !0x0003);
+}
+
+/// Walk the standard (< 256 byte) capability linked list starting at the
+/// pointer in 0x34, looking for `target_id`.
+fn find_cap(bus: u8, dev: u8, func: u8, target_id: u8) -> Option<u8> {
     let status = r16(bus, dev, func, 0x06);
     if (status & 0x10) == 0 {
         return None;
@@ -112,7 +154,7 @@ fn find_pcie_cap(bus: u8, dev: u8, func: u8) -> Option<u8> {
     let mut cap_ptr = r8(bus, dev, func, 0x34) & 0xFC;
     while cap_ptr != 0 {
         let cap_id = r8(bus, dev, func, cap_ptr as u16);
-        if cap_id == 0x10 {
+        if cap_id == target_id {
             return Some(cap_ptr);
         }
         cap_ptr = r8(bus, dev, func, (cap_ptr + 1) as u16) & 0xFC;
@@ -120,6 +162,417 @@ fn find_pcie_cap(bus: u8, dev: u8, func: u8) -> Option<u8> {
     None
 }
 
+fn find_pcie_cap(bus: u8, dev: u8, func: u8) -> Option<u8> {
+    find_cap(bus, dev, func, 0x10)
+}
+
+const PM_CAP_ID: u8 = 0x01;
+
+/// Some eGPU functions (the GPU and especially the HDA audio function) can
+/// power up in D3hot after a cold plug, which silently drops config writes
+/// to their BARs. Force D0 and give the device time to come up before we
+/// touch anything else on it.
+fn ensure_d0(bus: u8, dev: u8, func: u8) {
+    let Some(cap) = find_cap(bus, dev, func, PM_CAP_ID) else {
+        return;
+    };
+    let pmcsr = r16(bus, dev, func, (cap + 0x04) as u16);
+    if pmcsr & 0x3 != 0 {
+        w16(bus, dev, func, (cap + 0x04) as u16, pmcsr & !0x3);
+        stall(Duration::from_millis(10));
+    }
+}
+
+/// How long we'll poll a PCIe Link Status register before giving up.
+const LINK_TRAIN_TIMEOUT_MS: u32 = 100;
+
+/// Set to `Some(1..=4)` to force a target link speed (1 = 2.5GT/s ... 4 =
+/// 16GT/s) via Link Control 2 before retraining. Useful for enclosures that
+/// negotiate a degraded speed on cold plug.
+const FORCE_LINK_SPEED: Option<u8> = None;
+
+#[derive(Debug)]
+enum LinkError {
+    Timeout,
+}
+
+/// Poll the Link Status register at `pcie_cap + 0x12` for Data Link Layer
+/// Link Active (bit 13), bounded by `LINK_TRAIN_TIMEOUT_MS`.
+fn wait_link_active(bus: u8, dev: u8, func: u8, pcie_cap: u8) -> Result<(), LinkError> {
+    for _ in 0..LINK_TRAIN_TIMEOUT_MS {
+        let link_status = r16(bus, dev, func, (pcie_cap + 0x12) as u16);
+        if link_status & (1 << 13) != 0 {
+            return Ok(());
+        }
+        stall(Duration::from_millis(1));
+    }
+    Err(LinkError::Timeout)
+}
+
+/// Set the Retrain Link bit (bit 5) in Link Control and wait for the Link
+/// Training bit (bit 11 of Link Status) to clear.
+fn retrain_link(bus: u8, dev: u8, func: u8, pcie_cap: u8) -> Result<(), LinkError> {
+    let link_ctrl = r16(bus, dev, func, (pcie_cap + 0x10) as u16);
+    w16(bus, dev, func, (pcie_cap + 0x10) as u16, link_ctrl | (1 << 5));
+
+    for _ in 0..LINK_TRAIN_TIMEOUT_MS {
+        let link_status = r16(bus, dev, func, (pcie_cap + 0x12) as u16);
+        if link_status & (1 << 11) == 0 {
+            return Ok(());
+        }
+        stall(Duration::from_millis(1));
+    }
+    Err(LinkError::Timeout)
+}
+
+/// Force the target link speed via Link Control 2 at `pcie_cap + 0x30`.
+fn force_link_speed(bus: u8, dev: u8, func: u8, pcie_cap: u8, speed: u8) {
+    let link_ctrl2 = r16(bus, dev, func, (pcie_cap + 0x30) as u16);
+    w16(
+        bus,
+        dev,
+        func,
+        (pcie_cap + 0x30) as u16,
+        (link_ctrl2 & !0xF) | (speed as u16 & 0xF),
+    );
+}
+
+/// Make sure the downstream link on `bus:dev.func` is actually up before we
+/// touch anything behind it, retraining (and optionally forcing a target
+/// speed) once if it isn't.
+fn bring_up_link(bus: u8, dev: u8, func: u8) -> Result<(), LinkError> {
+    let Some(pcie_cap) = find_pcie_cap(bus, dev, func) else {
+        return Ok(()); // no PCIe capability, nothing to poll
+    };
+
+    if wait_link_active(bus, dev, func, pcie_cap).is_ok() {
+        return Ok(());
+    }
+
+    if let Some(speed) = FORCE_LINK_SPEED {
+        force_link_speed(bus, dev, func, pcie_cap, speed);
+    }
+    retrain_link(bus, dev, func, pcie_cap)?;
+    wait_link_active(bus, dev, func, pcie_cap)
+}
+
+const AER_CAP_ID: u16 = 0x0001;
+
+// Surprise Down Error Mask (bit 5 of the Uncorrectable Error Mask register).
+// eGPU enclosures over Thunderbolt/external cabling routinely trip this on
+// hotplug/replug; masking it keeps a spurious surprise-removal from wedging
+// an otherwise-healthy link.
+const AER_UNCORR_SURPRISE_DOWN_MASK: u32 = 1 << 5;
+const AER_UNCORR_SURPRISE_DOWN_SEVERITY: u32 = 1 << 5;
+
+/// Clear whatever AER status bits are currently latched (by writing back
+/// what was read, which is how status-on-write-1-to-clear registers work)
+/// and mask surprise-down errors. Returns the pre-clear (uncorrectable,
+/// correctable) status for diagnostics, or `None` if the device has no AER
+/// capability.
+fn setup_aer(bus: u8, dev: u8, func: u8) -> Option<(u32, u32)> {
+    let cap = find_ext_cap(bus, dev, func, AER_CAP_ID)?;
+
+    let uncorr_status = r32_ext(bus, dev, func, cap + 0x04);
+    w32_ext(bus, dev, func, cap + 0x04, uncorr_status);
+
+    let corr_status = r32_ext(bus, dev, func, cap + 0x10);
+    w32_ext(bus, dev, func, cap + 0x10, corr_status);
+
+    let uncorr_mask = r32_ext(bus, dev, func, cap + 0x08);
+    w32_ext(
+        bus,
+        dev,
+        func,
+        cap + 0x08,
+        uncorr_mask | AER_UNCORR_SURPRISE_DOWN_MASK,
+    );
+
+    // Masking isn't enough on its own: some root ports escalate to a fatal
+    // error off the severity register regardless of the mask bit, so also
+    // downgrade Surprise Down there.
+    let uncorr_severity = r32_ext(bus, dev, func, cap + 0x0C);
+    w32_ext(
+        bus,
+        dev,
+        func,
+        cap + 0x0C,
+        uncorr_severity & !AER_UNCORR_SURPRISE_DOWN_SEVERITY,
+    );
+
+    Some((uncorr_status, corr_status))
+}
+
+const ACS_CAP_ID: u16 = 0x000D;
+
+// ACS Capability / Control register bits: Source Validation, Translation
+// Blocking, P2P Request Redirect, P2P Completion Redirect, Upstream
+// Forwarding. Mirrors the set Linux's pci_enable_acs() turns on.
+const ACS_SV: u16 = 1 << 0;
+const ACS_TB: u16 = 1 << 1;
+const ACS_RR: u16 = 1 << 2;
+const ACS_CR: u16 = 1 << 3;
+const ACS_UF: u16 = 1 << 4;
+const ACS_WANTED: u16 = ACS_SV | ACS_TB | ACS_RR | ACS_CR | ACS_UF;
+
+/// Enable every wanted ACS bit that the switch bridge actually advertises as
+/// supported in its ACS Capability register, so the eGPU's downstream
+/// devices land in their own IOMMU group for passthrough.
+fn setup_acs(bus: u8, dev: u8, func: u8) -> Option<()> {
+    let cap = find_ext_cap(bus, dev, func, ACS_CAP_ID)?;
+
+    let acs_cap = r16_ext(bus, dev, func, cap + 0x04);
+    let acs_ctrl = r16_ext(bus, dev, func, cap + 0x06);
+    let supported = acs_cap & ACS_WANTED;
+    w16_ext(bus, dev, func, cap + 0x06, acs_ctrl | supported);
+
+    Some(())
+}
+
+/// Kind of address space a BAR decodes into, used to route it to the right
+/// bump allocator and bridge window.
+#[derive(Clone, Copy, PartialEq)]
+enum BarKind {
+    Io,
+    Mmio32,
+    Mmio64,
+    Mmio64Pref,
+}
+
+/// Result of sizing a single BAR (or the expansion ROM BAR).
+#[derive(Clone, Copy)]
+struct BarSize {
+    offset: u8,
+    kind: BarKind,
+    size: u64,
+}
+
+/// Probe a BAR's size using the standard write-all-ones / read-back trick:
+/// save the original value, write `0xFFFF_FFFF`, read back and mask off the
+/// decode bits, then `size = (!masked) + 1`. Returns `None` if the BAR is
+/// unimplemented (reads back all zero after masking).
+fn size_bar(bus: u8, dev: u8, func: u8, offset: u8) -> Option<BarSize> {
+    let orig_lo = r32(bus, dev, func, offset as u16);
+
+    if orig_lo & 0x1 == 1 {
+        w32(bus, dev, func, offset as u16, 0xFFFF_FFFF);
+        let probed = r32(bus, dev, func, offset as u16);
+        w32(bus, dev, func, offset as u16, orig_lo);
+
+        let masked = probed & !0x3;
+        if masked == 0 {
+            return None;
+        }
+        let size = (!masked).wrapping_add(1) as u64;
+        return Some(BarSize {
+            offset,
+            kind: BarKind::Io,
+            size,
+        });
+    }
+
+    let is_64 = (orig_lo >> 1) & 0x3 == 0b10;
+    let is_pref = (orig_lo >> 3) & 0x1 == 1;
+
+    if is_64 {
+        let hi_offset = offset + 4;
+        let orig_hi = r32(bus, dev, func, hi_offset as u16);
+
+        w32(bus, dev, func, offset as u16, 0xFFFF_FFFF);
+        w32(bus, dev, func, hi_offset as u16, 0xFFFF_FFFF);
+        let probed_lo = r32(bus, dev, func, offset as u16);
+        let probed_hi = r32(bus, dev, func, hi_offset as u16);
+        w32(bus, dev, func, offset as u16, orig_lo);
+        w32(bus, dev, func, hi_offset as u16, orig_hi);
+
+        let masked = ((probed_hi as u64) << 32) | (probed_lo as u64 & !0xF);
+        if masked == 0 {
+            return None;
+        }
+        let size = (!masked).wrapping_add(1);
+        let kind = if is_pref {
+            BarKind::Mmio64Pref
+        } else {
+            BarKind::Mmio64
+        };
+        Some(BarSize {
+            offset,
+            kind,
+            size,
+        })
+    } else {
+        w32(bus, dev, func, offset as u16, 0xFFFF_FFFF);
+        let probed = r32(bus, dev, func, offset as u16);
+        w32(bus, dev, func, offset as u16, orig_lo);
+
+        let masked = probed & !0xF;
+        if masked == 0 {
+            return None;
+        }
+        let size = (!masked).wrapping_add(1) as u64;
+        Some(BarSize {
+            offset,
+            kind: BarKind::Mmio32,
+            size,
+        })
+    }
+}
+
+/// Probe the expansion ROM BAR at `offset` (bit 0 is the enable bit rather
+/// than a decode-type bit, so it gets its own sizing routine).
+fn size_rom_bar(bus: u8, dev: u8, func: u8, offset: u8) -> Option<u64> {
+    let orig = r32(bus, dev, func, offset as u16);
+    w32(bus, dev, func, offset as u16, 0xFFFF_FFFE); // keep enable bit clear while probing
+    let probed = r32(bus, dev, func, offset as u16);
+    w32(bus, dev, func, offset as u16, orig);
+
+    let masked = probed & 0xFFFF_F800;
+    if masked == 0 {
+        return None;
+    }
+    Some((!masked).wrapping_add(1) as u64)
+}
+
+/// Size every implemented BAR of a function, plus its expansion ROM BAR if
+/// `rom_offset` is given, storing each one found in the next free slot of
+/// `out`.
+/// `last_bar_offset` is 0x14 for a type 1 (bridge) header, which only has
+/// two BAR slots, or 0x24 for a type 0 (endpoint) header's six.
+fn size_function_bars(
+    bus: u8,
+    dev: u8,
+    func: u8,
+    last_bar_offset: u8,
+    rom_offset: Option<u8>,
+    out: &mut [Option<BarSize>; 7],
+) {
+    let mut i = 0;
+    let mut offset = 0x10u8;
+    while offset <= last_bar_offset {
+        if let Some(bar) = size_bar(bus, dev, func, offset) {
+            let skip_next = bar.kind != BarKind::Io && (r32(bus, dev, func, offset as u16) >> 1) & 0x3 == 0b10;
+            out[i] = Some(bar);
+            i += 1;
+            offset += if skip_next { 8 } else { 4 };
+        } else {
+            offset += 4;
+        }
+    }
+    if let Some(rom_offset) = rom_offset {
+        if let Some(size) = size_rom_bar(bus, dev, func, rom_offset) {
+            out[i] = Some(BarSize {
+                offset: rom_offset,
+                kind: BarKind::Mmio32,
+                size,
+            });
+        }
+    }
+}
+
+/// Simple per-window-type bump allocator. Each allocation is aligned to its
+/// own size, which is always a power of two for PCI BARs.
+struct BarAllocator {
+    io: u32,
+    mmio32: u32,
+    pref64: u64,
+}
+
+impl BarAllocator {
+    const fn new() -> Self {
+        Self {
+            io: IO_WINDOW_BASE,
+            mmio32: MMIO_WINDOW_BASE,
+            pref64: PREF_WINDOW_BASE,
+        }
+    }
+
+    fn alloc(&mut self, bar: BarSize) -> u64 {
+        match bar.kind {
+            BarKind::Io => {
+                let base = align_up(self.io as u64, bar.size) as u32;
+                self.io = base + bar.size as u32;
+                base as u64
+            }
+            BarKind::Mmio32 | BarKind::Mmio64 => {
+                // Non-prefetchable bridge windows are architecturally 32-bit
+                // only, so a 64-bit non-prefetchable BAR still has to land in
+                // the 32-bit pool; its high dword is written as zero below.
+                let base = align_up(self.mmio32 as u64, bar.size) as u32;
+                self.mmio32 = base + bar.size as u32;
+                base as u64
+            }
+            BarKind::Mmio64Pref => {
+                let base = align_up(self.pref64, bar.size);
+                self.pref64 = base + bar.size;
+                base
+            }
+        }
+    }
+
+    /// Round every bump pointer up to the hardware window granularity a
+    /// bridge's base/limit registers decode at. Must be called once a
+    /// bridge's whole subtree has finished allocating, before its parent
+    /// moves on to the next sibling — otherwise the next sibling's BARs can
+    /// land inside the range this bridge's base/limit registers round up to
+    /// decode, aliasing two unrelated branches onto the same physical
+    /// addresses.
+    fn close_bridge_window(&mut self) {
+        self.io = align_up(self.io as u64, IO_WINDOW_GRANULARITY) as u32;
+        self.mmio32 = align_up(self.mmio32 as u64, MEM_WINDOW_GRANULARITY) as u32;
+        self.pref64 = align_up(self.pref64, PREF_WINDOW_GRANULARITY);
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Size and program every BAR of a function (endpoint or bridge) with an
+/// address carved out of `alloc`, enabling decode afterwards. Returns the
+/// window its own BARs occupy, for the caller to fold into the parent
+/// bridge's forwarded range.
+fn assign_function_bars(
+    bus: u8,
+    dev: u8,
+    func: u8,
+    last_bar_offset: u8,
+    rom_offset: Option<u8>,
+    alloc: &mut BarAllocator,
+) -> BridgeWindow {
+    disable_decode(bus, dev, func);
+
+    let mut bars: [Option<BarSize>; 7] = [None; 7];
+    size_function_bars(bus, dev, func, last_bar_offset, rom_offset, &mut bars);
+
+    let mut window = BridgeWindow::empty();
+    for bar in bars.iter().flatten() {
+        let addr = alloc.alloc(*bar);
+        match bar.kind {
+            BarKind::Io => {
+                w32(bus, dev, func, bar.offset as u16, (addr as u32) | 0x1);
+            }
+            BarKind::Mmio32 if Some(bar.offset) == rom_offset => {
+                w32(bus, dev, func, bar.offset as u16, (addr as u32) | 0x1); // ROM enable bit
+            }
+            BarKind::Mmio32 => {
+                w32(bus, dev, func, bar.offset as u16, addr as u32);
+            }
+            BarKind::Mmio64 => {
+                w32(bus, dev, func, bar.offset as u16, (addr as u32) | 0x4);
+                w32(bus, dev, func, (bar.offset + 4) as u16, (addr >> 32) as u32);
+            }
+            BarKind::Mmio64Pref => {
+                w32(bus, dev, func, bar.offset as u16, (addr as u32) | 0xC);
+                w32(bus, dev, func, (bar.offset + 4) as u16, (addr >> 32) as u32);
+            }
+        }
+        window.note(*bar, addr);
+    }
+
+    enable_cmd(bus, dev, func);
+    window
+}
+
 #[cfg(debug_assertions)]
 fn debug_scan_all_buses() {
     println!("=== PCI Bus Scan ===");
@@ -147,6 +600,14 @@ fn debug_scan_all_buses() {
                         "{:02X}:{:02X}.{} {:04X}:{:04X} {}",
                         bus, dev, func, vendor_id, device_id, aspm_str
                     );
+                    if let Some(aer_cap) = find_ext_cap(bus, dev, func, AER_CAP_ID) {
+                        let uncorr_status = r32_ext(bus, dev, func, aer_cap + 0x04);
+                        let corr_status = r32_ext(bus, dev, func, aer_cap + 0x10);
+                        println!(
+                            "           AER uncorrectable={:#010X} correctable={:#010X}",
+                            uncorr_status, corr_status
+                        );
+                    }
                 }
                 if func == 0 {
                     let header = r8(bus, dev, 0, 0x0E);
@@ -173,132 +634,199 @@ fn disable_aspm_root_port() {
     }
 }
 
-fn config_bridge_root() {
-    // Root port 00:02.1 - larger window to include switch BAR
-    w8(0x00, 0x02, 0x01, 0x18, 0x00); // Primary = 0
-    w8(0x00, 0x02, 0x01, 0x19, BUS_UPSTREAM); // Secondary = 8
-    w8(0x00, 0x02, 0x01, 0x1A, BUS_GPU); // Subordinate = 10
-
-    w8(0x00, 0x02, 0x01, 0x1C, IO_BASE);
-    w8(0x00, 0x02, 0x01, 0x1D, IO_LIMIT);
-
-    w16(0x00, 0x02, 0x01, 0x20, MEM_BASE);
-    w16(0x00, 0x02, 0x01, 0x22, MEM_LIMIT_ROOT); // Larger limit
-
-    w16(0x00, 0x02, 0x01, 0x24, PREF_BASE_LO);
-    w16(0x00, 0x02, 0x01, 0x26, PREF_LIMIT_LO);
-    w32(0x00, 0x02, 0x01, 0x28, PREF_BASE_HI);
-    w32(0x00, 0x02, 0x01, 0x2C, PREF_LIMIT_HI);
-
-    enable_cmd(0x00, 0x02, 0x01);
+/// Bridge window, derived from the union of everything allocated behind it
+/// rather than hand-picked constants.
+struct BridgeWindow {
+    io_lo: u32,
+    io_hi: u32,
+    mem_lo: u32,
+    mem_hi: u32,
+    pref_lo: u64,
+    pref_hi: u64,
 }
 
-fn config_bridge_upstream() {
-    // Upstream switch 08:00.0 - window for downstream only
-    if (r32(BUS_UPSTREAM, 0, 0, 0x00) & 0xFFFF) != 0x1002 {
-        return;
+impl BridgeWindow {
+    const fn empty() -> Self {
+        Self {
+            io_lo: u32::MAX,
+            io_hi: 0,
+            mem_lo: u32::MAX,
+            mem_hi: 0,
+            pref_lo: u64::MAX,
+            pref_hi: 0,
+        }
     }
 
-    // Assign switch's own BAR first
-    w32(BUS_UPSTREAM, 0, 0, 0x10, SWITCH_BAR0);
-
-    w8(BUS_UPSTREAM, 0, 0, 0x18, BUS_UPSTREAM);
-    w8(BUS_UPSTREAM, 0, 0, 0x19, BUS_DOWNSTREAM);
-    w8(BUS_UPSTREAM, 0, 0, 0x1A, BUS_GPU);
-
-    w8(BUS_UPSTREAM, 0, 0, 0x1C, IO_BASE);
-    w8(BUS_UPSTREAM, 0, 0, 0x1D, IO_LIMIT);
-
-    w16(BUS_UPSTREAM, 0, 0, 0x20, MEM_BASE);
-    w16(BUS_UPSTREAM, 0, 0, 0x22, MEM_LIMIT_CHILD); // Smaller limit, excludes own BAR
-
-    w16(BUS_UPSTREAM, 0, 0, 0x24, PREF_BASE_LO);
-    w16(BUS_UPSTREAM, 0, 0, 0x26, PREF_LIMIT_LO);
-    w32(BUS_UPSTREAM, 0, 0, 0x28, PREF_BASE_HI);
-    w32(BUS_UPSTREAM, 0, 0, 0x2C, PREF_LIMIT_HI);
-
-    enable_cmd(BUS_UPSTREAM, 0, 0);
-}
-
-fn config_bridge_downstream() {
-    // Downstream switch 09:00.0
-    if (r32(BUS_DOWNSTREAM, 0, 0, 0x00) & 0xFFFF) != 0x1002 {
-        return;
+    fn note(&mut self, bar: BarSize, addr: u64) {
+        match bar.kind {
+            BarKind::Io => {
+                let lo = addr as u32;
+                let hi = lo + bar.size as u32 - 1;
+                self.io_lo = self.io_lo.min(lo);
+                self.io_hi = self.io_hi.max(hi);
+            }
+            BarKind::Mmio32 | BarKind::Mmio64 => {
+                let lo = addr as u32;
+                let hi = lo + bar.size as u32 - 1;
+                self.mem_lo = self.mem_lo.min(lo);
+                self.mem_hi = self.mem_hi.max(hi);
+            }
+            BarKind::Mmio64Pref => {
+                let hi = addr + bar.size - 1;
+                self.pref_lo = self.pref_lo.min(addr);
+                self.pref_hi = self.pref_hi.max(hi);
+            }
+        }
     }
 
-    w8(BUS_DOWNSTREAM, 0, 0, 0x18, BUS_DOWNSTREAM);
-    w8(BUS_DOWNSTREAM, 0, 0, 0x19, BUS_GPU);
-    w8(BUS_DOWNSTREAM, 0, 0, 0x1A, BUS_GPU);
-
-    w8(BUS_DOWNSTREAM, 0, 0, 0x1C, IO_BASE);
-    w8(BUS_DOWNSTREAM, 0, 0, 0x1D, IO_LIMIT);
+    /// Fold a descendant's window into this one.
+    fn merge(&mut self, other: &BridgeWindow) {
+        self.io_lo = self.io_lo.min(other.io_lo);
+        self.io_hi = self.io_hi.max(other.io_hi);
+        self.mem_lo = self.mem_lo.min(other.mem_lo);
+        self.mem_hi = self.mem_hi.max(other.mem_hi);
+        self.pref_lo = self.pref_lo.min(other.pref_lo);
+        self.pref_hi = self.pref_hi.max(other.pref_hi);
+    }
 
-    w16(BUS_DOWNSTREAM, 0, 0, 0x20, MEM_BASE);
-    w16(BUS_DOWNSTREAM, 0, 0, 0x22, MEM_LIMIT_CHILD); // Same as upstream child window
+    fn write(&self, bus: u8, dev: u8, func: u8) {
+        if self.io_hi >= self.io_lo {
+            // I/O base/limit registers are 4KB-granular: bits [7:4] hold
+            // address bits [15:12]. Round the allocated range out to whole
+            // 4KB blocks before encoding it.
+            let io_base_4k = self.io_lo & !0xFFF;
+            let io_limit_4k = self.io_hi | 0xFFF;
+            w8(bus, dev, func, 0x1C, ((io_base_4k >> 8) & 0xF0) as u8);
+            w8(bus, dev, func, 0x1D, ((io_limit_4k >> 8) & 0xF0) as u8);
+        } else {
+            // base > limit marks an empty window when nothing behind this
+            // bridge claimed any I/O space.
+            w8(bus, dev, func, 0x1C, 0xF0);
+            w8(bus, dev, func, 0x1D, 0x00);
+        }
 
-    w16(BUS_DOWNSTREAM, 0, 0, 0x24, PREF_BASE_LO);
-    w16(BUS_DOWNSTREAM, 0, 0, 0x26, PREF_LIMIT_LO);
-    w32(BUS_DOWNSTREAM, 0, 0, 0x28, PREF_BASE_HI);
-    w32(BUS_DOWNSTREAM, 0, 0, 0x2C, PREF_LIMIT_HI);
+        if self.mem_hi >= self.mem_lo {
+            w16(bus, dev, func, 0x20, (self.mem_lo >> 16) as u16);
+            w16(bus, dev, func, 0x22, (self.mem_hi >> 16) as u16);
+        }
 
-    enable_cmd(BUS_DOWNSTREAM, 0, 0);
+        if self.pref_hi >= self.pref_lo {
+            w16(bus, dev, func, 0x24, ((self.pref_lo >> 16) as u16) | 0x1);
+            w16(bus, dev, func, 0x26, ((self.pref_hi >> 16) as u16) | 0x1);
+            w32(bus, dev, func, 0x28, (self.pref_lo >> 32) as u32);
+            w32(bus, dev, func, 0x2C, (self.pref_hi >> 32) as u32);
+        }
+    }
 }
 
-fn config_bridges() {
-    config_bridge_root();
-    stall(Duration::from_millis(20));
-
-    config_bridge_upstream();
-    stall(Duration::from_millis(20));
-
-    config_bridge_downstream();
-    stall(Duration::from_millis(20));
+fn is_bridge_header(bus: u8, dev: u8, func: u8) -> bool {
+    (r8(bus, dev, func, 0x0E) & 0x7F) == 0x01
 }
 
-fn config_gpu_bars() {
-    let bus = BUS_GPU;
-
-    // BAR0: 256M @ 0x1030000000 (64-bit pref)
-    w32(bus, 0, 0, 0x10, GPU_BAR0_LO);
-    w32(bus, 0, 0, 0x14, GPU_BAR0_HI);
+/// Depth-first enumeration and bring-up of one bridge and everything behind
+/// it, modeled as upstream/downstream links the way coreboot's device tree
+/// does: assign this bridge's secondary bus number from `next_bus`, widen
+/// the subordinate limit while we recurse so downstream traffic isn't
+/// filtered mid-scan, then narrow it back down once the subtree is fully
+/// numbered. Returns the window (I/O / non-prefetch MMIO / prefetchable MMIO)
+/// this bridge's own BARs plus its whole subtree need the parent to route.
+fn configure_bridge_subtree(
+    bus: u8,
+    dev: u8,
+    func: u8,
+    next_bus: &mut u8,
+    alloc: &mut BarAllocator,
+) -> BridgeWindow {
+    // Bus numbers are 8-bit; an enclosure with enough nested switches to
+    // exhaust them would otherwise wrap *next_bus and start reusing numbers
+    // already claimed higher up the tree. Bail out and leave this bridge
+    // unconfigured rather than risk that.
+    if *next_bus == u8::MAX {
+        return BridgeWindow::empty();
+    }
+    let secondary = *next_bus;
+    *next_bus += 1;
+
+    ensure_d0(bus, dev, func);
+
+    w8(bus, dev, func, 0x18, bus);
+    w8(bus, dev, func, 0x19, secondary);
+    w8(bus, dev, func, 0x1A, 0xFF); // provisional, narrowed once the subtree is numbered
+
+    // Decode stays off until assign_function_bars() re-enables it once this
+    // bridge's own BARs are sized and programmed; enabling it earlier would
+    // let the all-ones sizing probe alias onto the bus.
+    let mut parent_window = assign_function_bars(bus, dev, func, 0x14, None, alloc);
+
+    if let Err(_err) = bring_up_link(bus, dev, func) {
+        // The link never came up; carry on and scan the secondary bus
+        // anyway (it'll just read back all-ones), but don't swallow the
+        // failure silently.
+        #[cfg(debug_assertions)]
+        println!(
+            "{:02X}:{:02X}.{} link never came up: {:?}",
+            bus, dev, func, _err
+        );
+    }
+    setup_aer(bus, dev, func);
+    setup_acs(bus, dev, func);
 
-    // BAR2: 2M @ 0x1040000000 (64-bit pref)
-    w32(bus, 0, 0, 0x18, GPU_BAR2_LO);
-    w32(bus, 0, 0, 0x1C, GPU_BAR2_HI);
+    let mut forward_window = BridgeWindow::empty();
 
-    // BAR4: I/O @ 0x2000
-    w32(bus, 0, 0, 0x20, GPU_BAR4);
+    for child_dev in 0..32u8 {
+        for child_func in 0..8u8 {
+            let vendor_device = r32(secondary, child_dev, child_func, 0x00);
+            if (vendor_device & 0xFFFF) == 0xFFFF {
+                if child_func == 0 {
+                    break; // no function 0 => device absent, skip the rest
+                }
+                continue;
+            }
 
-    // BAR5: 1M MMIO @ 0xE0200000
-    w32(bus, 0, 0, 0x24, GPU_BAR5);
+            if is_bridge_header(secondary, child_dev, child_func) {
+                let child_window =
+                    configure_bridge_subtree(secondary, child_dev, child_func, next_bus, alloc);
+                forward_window.merge(&child_window);
+            } else {
+                ensure_d0(secondary, child_dev, child_func);
+                let ep_window =
+                    assign_function_bars(secondary, child_dev, child_func, 0x24, Some(0x30), alloc);
+                setup_aer(secondary, child_dev, child_func);
+                forward_window.merge(&ep_window);
+            }
 
-    // Expansion ROM @ 0xE0300000 (enable)
-    w32(bus, 0, 0, 0x30, GPU_ROM);
+            if child_func == 0 {
+                let header = r8(secondary, child_dev, 0, 0x0E);
+                if (header & 0x80) == 0 {
+                    break; // not a multi-function device
+                }
+            }
+        }
+    }
 
-    enable_cmd(bus, 0, 0);
+    // All bus numbers handed out anywhere in this subtree are <= next_bus - 1.
+    w8(bus, dev, func, 0x1A, *next_bus - 1);
+    forward_window.write(bus, dev, func);
 
-    //Audio function (01)
-    w32(bus, 0, 1, 0x10, AUDIO_BAR0);
-    let cmd = r16(bus, 0, 1, 0x04);
-    w16(bus, 0, 1, 0x04, cmd | 0x0006); // MEM + BUS_MASTER
+    parent_window.merge(&forward_window);
 
-    enable_cmd(bus, 0, 1);
-    stall(Duration::from_millis(20));
-}
+    // This subtree is fully allocated and its base/limit registers are
+    // programmed; push the bump pointers past the granularity boundary
+    // those registers round up to before the parent allocates the next
+    // sibling.
+    alloc.close_bridge_window();
 
-fn config_upstream_switch() {
-    // Assign BAR0 to upstream switch
-    w32(BUS_UPSTREAM, 0, 0, 0x10, SWITCH_BAR0);
-    stall(Duration::from_millis(20));
+    parent_window
 }
 
 #[entry]
 fn main() -> Status {
     uefi::helpers::init().unwrap();
 
-    config_bridges();
-    config_upstream_switch();
-    config_gpu_bars();
+    let mut next_bus = 1u8;
+    let mut alloc = BarAllocator::new();
+    configure_bridge_subtree(0x00, 0x02, 0x01, &mut next_bus, &mut alloc);
+    stall(Duration::from_millis(20));
 
     disable_aspm_root_port();
 